@@ -1,3 +1,4 @@
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::fmt;
 
@@ -5,35 +6,254 @@ use rand::prelude::*;
 
 use key::{Id, InsertionStrategy, Key, INITIAL_WIDTH};
 
+/// A single slot of a `Key`'s path: the `(position, Id)` digit at one trie level.
+type Digit<SiteId> = (usize, Id<SiteId>);
+
+/// What a trie node holds when some `Key` terminates exactly there.
+#[derive(Debug)]
+struct Entry<Value> {
+    value: Option<Value>,
+    clock: usize,
+}
+
+/// A node of the digit trie backing `Document`. Each `Key` is a path of
+/// `Digit`s from the root; in-order traversal (own entry, then children in
+/// map order) yields the same order as `Key::cmp`, since a node's entry is
+/// always equivalent to the smallest possible continuation of its path.
+///
+/// `count` is the number of live (non-tombstone) entries in the subtree
+/// rooted here, including this node's own entry; it is kept in sync on every
+/// insert/remove so `nth`/`rank` can descend level by level, skipping whole
+/// subtrees by their counts instead of walking the whole trie. Each level
+/// still scans that node's children in order, so a lookup costs the total
+/// number of children scanned along the path, not O(depth).
+#[derive(Debug)]
+struct TrieNode<SiteId, Value> {
+    entry: Option<Entry<Value>>,
+    count: usize,
+    children: BTreeMap<Digit<SiteId>, TrieNode<SiteId, Value>>,
+}
+
+impl<SiteId: Ord + Clone, Value> TrieNode<SiteId, Value> {
+    fn new() -> Self {
+        TrieNode {
+            entry: None,
+            count: 0,
+            children: BTreeMap::new(),
+        }
+    }
+
+    fn is_live(entry: &Option<Entry<Value>>) -> bool {
+        match entry {
+            Some(entry) => entry.value.is_some(),
+            None => false,
+        }
+    }
+
+    fn get(&self, path: &[Digit<SiteId>]) -> Option<&Value> {
+        match path.split_first() {
+            None => self.entry.as_ref().and_then(|entry| entry.value.as_ref()),
+            Some((digit, rest)) => self.children.get(digit).and_then(|child| child.get(rest)),
+        }
+    }
+
+    /// Inserts `value` at `path`, keeping whichever of the old and new entry
+    /// has the larger clock (ties keep the existing entry). `value` may be
+    /// `None` to leave a tombstone, which can also retire a previously-live
+    /// entry, so `count` is adjusted both up and down.
+    fn insert(&mut self, path: &[Digit<SiteId>], value: Option<Value>, clock: usize) {
+        match path.split_first() {
+            None => {
+                let was_live = Self::is_live(&self.entry);
+                match &self.entry {
+                    Some(entry) if entry.clock >= clock => {}
+                    _ => self.entry = Some(Entry { value, clock }),
+                }
+                let is_live = Self::is_live(&self.entry);
+                if is_live && !was_live {
+                    self.count += 1;
+                } else if was_live && !is_live {
+                    self.count -= 1;
+                }
+            }
+            Some((digit, rest)) => {
+                let child = self
+                    .children
+                    .entry(digit.clone())
+                    .or_insert_with(TrieNode::new);
+                let before = child.count;
+                child.insert(rest, value, clock);
+                let after = child.count;
+                if after >= before {
+                    self.count += after - before;
+                } else {
+                    self.count -= before - after;
+                }
+            }
+        }
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Value> + 'a> {
+        let own = self.entry.iter().filter_map(|entry| entry.value.as_ref());
+        let children = self.children.values().flat_map(TrieNode::iter);
+        Box::new(own.chain(children))
+    }
+
+    /// Counts entries holding a tombstone (`value: None`), including the
+    /// document's two structural sentinels.
+    fn none_count(&self) -> usize {
+        let own = match &self.entry {
+            Some(entry) if entry.value.is_none() => 1,
+            _ => 0,
+        };
+        own + self.children.values().map(TrieNode::none_count).sum::<usize>()
+    }
+
+    /// Physically drops tombstones whose removing clock is below
+    /// `threshold`, except for `protected` paths (the document's sentinels,
+    /// which carry clock `0` but aren't actually garbage). Returns whether
+    /// this node is now empty and can be pruned by its parent.
+    ///
+    /// Dropping a tombstone's entry erases its clock along with it, so a
+    /// path this collects can no longer reject a delivery with a clock
+    /// below `threshold` -- see `Document::collect_garbage`.
+    fn collect_garbage(
+        &mut self,
+        threshold: usize,
+        path: &mut Vec<Digit<SiteId>>,
+        protected: &[Vec<Digit<SiteId>>],
+    ) -> bool {
+        let collectible = match &self.entry {
+            Some(entry) => entry.value.is_none() && entry.clock < threshold,
+            None => false,
+        };
+        if collectible && !protected.iter().any(|p| p == path) {
+            self.entry = None;
+        }
+
+        let mut emptied = vec![];
+        for (digit, child) in self.children.iter_mut() {
+            path.push(digit.clone());
+            if child.collect_garbage(threshold, path, protected) {
+                emptied.push(digit.clone());
+            }
+            path.pop();
+        }
+        for digit in emptied {
+            self.children.remove(&digit);
+        }
+
+        self.entry.is_none() && self.children.is_empty()
+    }
+
+    /// Descends via subtree counts to the `index`-th live entry, in the same
+    /// order as `iter`.
+    fn nth(&self, index: usize) -> Option<&Value> {
+        let mut remaining = index;
+        if let Some(value) = self.entry.as_ref().and_then(|entry| entry.value.as_ref()) {
+            if remaining == 0 {
+                return Some(value);
+            }
+            remaining -= 1;
+        }
+        for child in self.children.values() {
+            if remaining < child.count {
+                return child.nth(remaining);
+            }
+            remaining -= child.count;
+        }
+        None
+    }
+
+    /// Counts the live entries ordered strictly before `path` (`path` itself
+    /// need not be present).
+    fn rank(&self, path: &[Digit<SiteId>]) -> usize {
+        match path.split_first() {
+            None => 0,
+            Some((digit, rest)) => {
+                let mut acc = if Self::is_live(&self.entry) { 1 } else { 0 };
+                for (d, child) in &self.children {
+                    match d.cmp(digit) {
+                        Ordering::Less => acc += child.count,
+                        Ordering::Equal => {
+                            acc += child.rank(rest);
+                            break;
+                        }
+                        Ordering::Greater => break,
+                    }
+                }
+                acc
+            }
+        }
+    }
+
+    /// Reconstructs the path and clock of the `index`-th live entry, for
+    /// callers that need a `Key` rather than just the value (e.g.
+    /// index-based insertion).
+    fn key_at(&self, index: usize, prefix: &mut Vec<Digit<SiteId>>) -> Option<(Vec<Digit<SiteId>>, usize)> {
+        let mut remaining = index;
+        if let Some(entry) = &self.entry {
+            if entry.value.is_some() {
+                if remaining == 0 {
+                    return Some((prefix.clone(), entry.clock));
+                }
+                remaining -= 1;
+            }
+        }
+        for (digit, child) in &self.children {
+            if remaining < child.count {
+                prefix.push(digit.clone());
+                let found = child.key_at(remaining, prefix);
+                prefix.pop();
+                return found;
+            }
+            remaining -= child.count;
+        }
+        None
+    }
+}
+
+/// A CRDT operation minted by `Document::insert`/`Document::remove`, meant to
+/// be shipped to other replicas and folded in with `apply_remote`.
+#[derive(Clone, Debug)]
+pub enum Op<SiteId, Value> {
+    Insert { key: Key<SiteId>, value: Value },
+    Remove { key: Key<SiteId> },
+}
+
+impl<SiteId, Value> Op<SiteId, Value> {
+    /// The clock this op was minted with, i.e. the value a replica should
+    /// pass to `Document::observed_up_to` once it has applied this op (and
+    /// every op before it).
+    pub fn clock(&self) -> usize {
+        match self {
+            Op::Insert { key, .. } => key.clock,
+            Op::Remove { key } => key.clock,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Document<SiteId, Value> {
-    content: BTreeMap<Key<SiteId>, Option<Value>>,
+    content: TrieNode<SiteId, Value>,
     strategies: Vec<InsertionStrategy>,
     clock: usize,
+    /// Per-site low-water marks reported via `observed_up_to`: the clock up
+    /// to which that site is known to have observed every operation.
+    observed: BTreeMap<SiteId, usize>,
 }
 
 impl<SiteId: Ord + Clone + fmt::Debug, Value> Document<SiteId, Value> {
     pub fn new() -> Self {
-        let mut content = BTreeMap::new();
-        content.insert(
-            Key {
-                position: vec![(0, Id::Sentinel)],
-                clock: 0,
-            },
-            None,
-        );
-        content.insert(
-            Key {
-                position: vec![(INITIAL_WIDTH, Id::Sentinel)],
-                clock: 0,
-            },
-            None,
-        );
+        let mut content = TrieNode::new();
+        content.insert(&[(0, Id::Sentinel)], None, 0);
+        content.insert(&[(INITIAL_WIDTH, Id::Sentinel)], None, 0);
 
         Document {
-            content: content,
+            content,
             strategies: vec![random()],
             clock: 2,
+            observed: BTreeMap::new(),
         }
     }
 
@@ -51,78 +271,179 @@ impl<SiteId: Ord + Clone + fmt::Debug, Value> Document<SiteId, Value> {
         }
     }
 
-    pub fn insert(
-        &mut self,
-        site_id: SiteId,
-        left: &Key<SiteId>,
-        right: &Key<SiteId>,
-        value: Value,
-    ) -> Key<SiteId> {
-        use std::collections::btree_map::Entry::*;
+    pub fn insert_at(&mut self, key: Key<SiteId>, value: Value) {
+        self.content.insert(&key.position, Some(value), key.clock);
+    }
 
-        let key = left.pick(right, Id::Site(site_id), self.clock, &mut self.strategies);
-        assert!(
-            left < &key && &key < right,
-            "must hold {:?} < {:?} < {:?}",
-            left,
-            key,
-            right
-        );
+    /// Tombstones `key` with a freshly minted clock instead of erasing it
+    /// outright, so a concurrently-delivered `Insert` at the same position
+    /// can still be ordered against the removal (see `apply_remote`). A no-op
+    /// if `key` was never live, rather than fabricating a tombstone for a
+    /// position nothing was ever inserted at.
+    pub fn remove(&mut self, key: &Key<SiteId>) -> Option<Op<SiteId, Value>> {
+        self.content.get(&key.position)?;
+
+        let clock = self.clock;
+        self.content.insert(&key.position, None, clock);
+        self.clock += 1;
 
-        match self.content.entry(key.clone()) {
-            Vacant(v) => {
-                v.insert(Some(value));
+        Some(Op::Remove {
+            key: Key {
+                position: key.position.clone(),
+                clock,
+            },
+        })
+    }
+
+    /// Integrates an `Op` generated by another replica. Applying the same
+    /// `Op` more than once, or applying a batch of ops out of order, always
+    /// converges to the same document: an `Insert` only overwrites an
+    /// existing entry (including a tombstone left by a `Remove`) if its
+    /// clock is strictly greater, which is exactly the rule `TrieNode::insert`
+    /// already enforces locally.
+    ///
+    /// This only holds up to a position's tombstone being garbage-collected
+    /// (see `collect_garbage`): once collected, its clock is gone, so a
+    /// stale `Insert` redelivered afterwards is treated as new and
+    /// resurrects the entry. Callers must not redeliver an op whose clock
+    /// is at or below a threshold they've already collected at.
+    pub fn apply_remote(&mut self, op: Op<SiteId, Value>) {
+        let key = match &op {
+            Op::Insert { key, .. } => key,
+            Op::Remove { key } => key,
+        };
+        self.clock = self.clock.max(key.clock) + 1;
+
+        match op {
+            Op::Insert { key, value } => {
+                self.content.insert(&key.position, Some(value), key.clock);
             }
-            Occupied(o) => {
-                assert_eq!(&key, o.key());
-                if o.key().clock < key.clock {
-                    o.remove_entry();
-                    self.content.insert(key.clone(), Some(value));
-                }
+            Op::Remove { key } => {
+                self.content.insert(&key.position, None, key.clock);
             }
         }
-        self.clock += 1;
-        key
     }
 
-    pub fn insert_at(&mut self, key: Key<SiteId>, value: Value) {
-        self.content.insert(key, Some(value));
+    /// Records that `site_id` has observed every operation up to `clock`,
+    /// i.e. a low-water mark for what that replica has seen. Never moves
+    /// backwards.
+    pub fn observed_up_to(&mut self, site_id: SiteId, clock: usize) {
+        let mark = self.observed.entry(site_id).or_insert(0);
+        *mark = (*mark).max(clock);
     }
 
-    pub fn remove(&mut self, key: Key<SiteId>) {
-        use std::collections::btree_map::Entry::*;
-        let clock = key.clock;
+    /// Returns how many tombstones are currently retained for convergence.
+    pub fn tombstone_count(&self) -> usize {
+        self.content.none_count() - 2
+    }
 
-        match self.content.entry(key) {
-            Vacant(_) => {},
-            Occupied(o) => {
-                if o.key().clock == clock {
-                    o.remove_entry();
-                }
-            }
-        }
+    /// Physically drops tombstones that every known site has observed being
+    /// superseded by, i.e. whose removing clock is below every reported
+    /// low-water mark. A tombstone dominated by every site's mark can no
+    /// longer lose a race to a concurrent `Insert`, since any such `Insert`
+    /// would have already been observed too. Does nothing until at least one
+    /// site has reported via `observed_up_to`.
+    ///
+    /// This assumes exactly-once delivery going forward for anything at or
+    /// below the collected threshold: collecting a tombstone erases its
+    /// clock, so `apply_remote`'s idempotence no longer holds for it, and a
+    /// redelivered (e.g. retried) op from before the threshold will
+    /// resurrect a removed entry instead of being rejected. Only call this
+    /// once `observed_up_to` marks are backed by a delivery channel that
+    /// itself never redelivers an op a site has already reported observing.
+    pub fn collect_garbage(&mut self) {
+        let threshold = match self.observed.values().min() {
+            Some(&threshold) => threshold,
+            None => return,
+        };
+        let protected = vec![self.start().position, self.end().position];
+        self.content
+            .collect_garbage(threshold, &mut Vec::new(), &protected);
     }
 
     pub fn get(&self, key: &Key<SiteId>) -> Option<&Value> {
-        self.content.get(key).and_then(Option::as_ref)
+        self.content.get(&key.position)
     }
 
     pub fn iter(&self) -> impl Iterator<Item = &Value> {
-        let start = self.start();
-        let end = self.end();
-        self.content
-            .iter()
-            .filter(move |item| item.0 != &start && item.0 != &end)
-            .map(|item| item.1.as_ref().unwrap())
+        self.content.iter()
     }
 
     pub fn len(&self) -> usize {
-        self.content.len() - 2
+        self.content.count
     }
 
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    /// Returns the `index`-th live value, in the same order as `iter`.
+    pub fn nth(&self, index: usize) -> Option<&Value> {
+        self.content.nth(index)
+    }
+
+    /// Returns how many live entries precede `key` in the document's order.
+    pub fn rank(&self, key: &Key<SiteId>) -> usize {
+        self.content.rank(&key.position)
+    }
+
+    fn key_at_index(&self, index: usize) -> Option<Key<SiteId>> {
+        self.content
+            .key_at(index, &mut Vec::new())
+            .map(|(position, clock)| Key { position, clock })
+    }
+
+    /// Removes the live entry currently at offset `index`.
+    pub fn remove_at_index(&mut self, index: usize) {
+        let key = self.key_at_index(index).expect("index out of bounds");
+        self.remove(&key);
+    }
+}
+
+impl<SiteId: Ord + Clone + fmt::Debug, Value: Clone> Document<SiteId, Value> {
+    pub fn insert(
+        &mut self,
+        site_id: SiteId,
+        left: &Key<SiteId>,
+        right: &Key<SiteId>,
+        value: Value,
+    ) -> Op<SiteId, Value> {
+        let key = left.pick(right, Id::Site(site_id), self.clock, &mut self.strategies);
+        assert!(
+            left < &key && &key < right,
+            "must hold {:?} < {:?} < {:?}",
+            left,
+            key,
+            right
+        );
+
+        self.content
+            .insert(&key.position, Some(value.clone()), key.clock);
+        self.clock += 1;
+        Op::Insert { key, value }
+    }
+
+    /// Inserts `value` so that it becomes the live entry at offset `index`,
+    /// by locating the live neighbors straddling `index` and picking a key
+    /// between them.
+    pub fn insert_at_index(&mut self, site_id: SiteId, index: usize, value: Value) -> Key<SiteId> {
+        let left = if index == 0 {
+            self.start()
+        } else {
+            self.key_at_index(index - 1)
+                .expect("index out of bounds")
+        };
+        let right = if index >= self.len() {
+            self.end()
+        } else {
+            self.key_at_index(index).expect("index out of bounds")
+        };
+
+        match self.insert(site_id, &left, &right, value) {
+            Op::Insert { key, .. } => key,
+            Op::Remove { .. } => unreachable!("Document::insert always produces an Op::Insert"),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -176,7 +497,10 @@ mod test {
                         left = iter.next().unwrap();
                         right = iter.next().unwrap();
                     }
-                    new_key = doc.insert(if rng.gen() { Alice } else { Bob }, left, right, value);
+                    new_key = match doc.insert(if rng.gen() { Alice } else { Bob }, left, right, value) {
+                        Op::Insert { key, .. } => key,
+                        Op::Remove { .. } => unreachable!(),
+                    };
                 }
 
                 keys.insert(new_key);
@@ -191,7 +515,7 @@ mod test {
                 };
 
                 keys.remove(&key);
-                doc.remove(key);
+                doc.remove(&key);
                 result.remove(i - 1);
             }
         }
@@ -207,14 +531,152 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_order_statistics() {
+        let mut doc = Document::new();
+        let start = doc.start();
+        let end = doc.end();
+
+        let mut keys = vec![];
+        keys.push(doc.insert_at_index((), 0, "a"));
+        keys.push(doc.insert_at_index((), 1, "c"));
+        keys.push(doc.insert_at_index((), 1, "b"));
+
+        assert_eq!(doc.iter().collect::<Vec<_>>(), vec![&"a", &"b", &"c"]);
+        assert_eq!(doc.nth(0), Some(&"a"));
+        assert_eq!(doc.nth(1), Some(&"b"));
+        assert_eq!(doc.nth(2), Some(&"c"));
+        assert_eq!(doc.nth(3), None);
+
+        assert_eq!(doc.rank(&start), 0);
+        assert_eq!(doc.rank(&keys[0]), 0);
+        assert_eq!(doc.rank(&keys[2]), 1);
+        assert_eq!(doc.rank(&keys[1]), 2);
+        assert_eq!(doc.rank(&end), 3);
+
+        doc.remove_at_index(1);
+        assert_eq!(doc.iter().collect::<Vec<_>>(), vec![&"a", &"c"]);
+        assert_eq!(doc.nth(1), Some(&"c"));
+    }
+
+    #[test]
+    fn test_apply_remote_converges_regardless_of_order() {
+        let mut local: Document<_, &str> = Document::new();
+        let start = local.start();
+        let end = local.end();
+
+        let insert_op = local.insert("site-a", &start, &end, "hello");
+        let key = match &insert_op {
+            Op::Insert { key, .. } => key.clone(),
+            Op::Remove { .. } => unreachable!(),
+        };
+        let remove_op = local.remove(&key).expect("key is live");
+
+        // Applying insert-then-remove, remove-then-insert, or either op
+        // twice must all converge to the same (empty) document.
+        let mut in_order: Document<_, &str> = Document::new();
+        in_order.apply_remote(insert_op.clone());
+        in_order.apply_remote(remove_op.clone());
+
+        let mut reordered: Document<_, &str> = Document::new();
+        reordered.apply_remote(remove_op.clone());
+        reordered.apply_remote(insert_op.clone());
+
+        let mut duplicated: Document<_, &str> = Document::new();
+        duplicated.apply_remote(insert_op.clone());
+        duplicated.apply_remote(insert_op);
+        duplicated.apply_remote(remove_op.clone());
+        duplicated.apply_remote(remove_op);
+
+        assert_eq!(local.iter().collect::<Vec<_>>(), Vec::<&&str>::new());
+        assert_eq!(in_order.iter().collect::<Vec<_>>(), Vec::<&&str>::new());
+        assert_eq!(reordered.iter().collect::<Vec<_>>(), Vec::<&&str>::new());
+        assert_eq!(duplicated.iter().collect::<Vec<_>>(), Vec::<&&str>::new());
+    }
+
+    #[test]
+    fn test_garbage_collection() {
+        let mut doc: Document<_, &str> = Document::new();
+        let start = doc.start();
+        let end = doc.end();
+
+        let a = doc.insert_at_index("me", 0, "a");
+        let _b = doc.insert_at_index("me", 1, "b");
+        let remove_op = doc.remove(&a).expect("key is live");
+        let tombstone_clock = match remove_op {
+            Op::Remove { key } => key.clock,
+            Op::Insert { .. } => unreachable!(),
+        };
+
+        assert_eq!(doc.tombstone_count(), 1);
+        assert_eq!(doc.iter().collect::<Vec<_>>(), vec![&"b"]);
+
+        // No site has reported anything yet: collecting must be a no-op,
+        // since nothing guarantees the tombstone can't still race a
+        // concurrent insert.
+        doc.collect_garbage();
+        assert_eq!(doc.tombstone_count(), 1);
+
+        // replica-1 hasn't caught up to the tombstone's clock yet: still
+        // not safe to collect.
+        doc.observed_up_to("replica-1", tombstone_clock - 1);
+        doc.observed_up_to("replica-2", tombstone_clock + 10);
+        doc.collect_garbage();
+        assert_eq!(doc.tombstone_count(), 1);
+
+        // Once every known site has observed past the tombstone's clock,
+        // it can be physically dropped without touching the live entries
+        // or the start/end sentinels.
+        doc.observed_up_to("replica-1", tombstone_clock + 10);
+        doc.collect_garbage();
+        assert_eq!(doc.tombstone_count(), 0);
+        assert_eq!(doc.iter().collect::<Vec<_>>(), vec![&"b"]);
+        assert_eq!(doc.get(&start), None);
+        assert_eq!(doc.get(&end), None);
+    }
+
+    #[test]
+    fn test_replay_after_garbage_collection_resurrects_removed_entry() {
+        // Characterizes the documented hazard on `Document::collect_garbage`:
+        // collecting a tombstone erases its clock, so redelivering an
+        // already-applied `Insert` from before the collected threshold is no
+        // longer rejected and resurrects the entry. This is only safe to
+        // trigger when the caller guarantees no such redelivery happens;
+        // this test pins down the behavior rather than "fixing" it away.
+        let mut doc: Document<_, &str> = Document::new();
+        let start = doc.start();
+        let end = doc.end();
+
+        let insert_op = doc.insert("site-a", &start, &end, "hello");
+        let key = match &insert_op {
+            Op::Insert { key, .. } => key.clone(),
+            Op::Remove { .. } => unreachable!(),
+        };
+        let remove_op = doc.remove(&key).expect("key is live");
+
+        doc.observed_up_to("site-a", remove_op.clock() + 1);
+        doc.collect_garbage();
+        assert_eq!(doc.tombstone_count(), 0);
+        assert_eq!(doc.iter().collect::<Vec<_>>(), Vec::<&&str>::new());
+
+        doc.apply_remote(insert_op);
+        assert_eq!(doc.iter().collect::<Vec<_>>(), vec![&"hello"]);
+    }
+
     #[test]
     fn test_hello_world() {
         let mut doc = Document::new();
         let start = doc.start();
         let end = doc.end();
 
-        let h = doc.insert((), &start, &end, "hello");
-        let e = doc.insert((), &h, &end, "!");
+        let h = match doc.insert((), &start, &end, "hello") {
+            Op::Insert { key, .. } => key,
+            Op::Remove { .. } => unreachable!(),
+        };
+        let e = match doc.insert((), &h, &end, "!") {
+            Op::Insert { key, .. } => key,
+            Op::Remove { .. } => unreachable!(),
+        };
         let _ = doc.insert((), &h, &e, "world");
 
         let mut iter = doc.iter();